@@ -1,12 +1,15 @@
 use anyhow::{Context, Result};
+use base64::Engine;
 use clap::Parser;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
+use sha2::{Digest, Sha256, Sha512};
 use std::fs::{self, File};
 use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
 use walkdir::WalkDir;
 
 #[derive(Parser, Debug)]
@@ -29,6 +32,17 @@ struct Args {
     #[arg(short, long)]
     mirror_url: Option<String>,
 
+    /// Bearer token for HTTP(S) mirrors (falls back to the BUCKOS_MIRROR_TOKEN env var).
+    /// Combined with --mirror-user, it's sent as the basic-auth password instead.
+    #[arg(long)]
+    mirror_token: Option<String>,
+
+    /// Basic-auth username for HTTP(S) mirrors (falls back to the BUCKOS_MIRROR_USER env var).
+    /// When set, --mirror-token (if any) is sent as the basic-auth password rather than a
+    /// bearer token.
+    #[arg(long)]
+    mirror_user: Option<String>,
+
     /// Package already-built targets without rebuilding
     #[arg(short, long)]
     skip_build: bool,
@@ -36,6 +50,65 @@ struct Args {
     /// Number of parallel packaging jobs (default: number of CPUs)
     #[arg(short, long)]
     jobs: Option<usize>,
+
+    /// Fetch and install prebuilt packages from a mirror instead of building
+    #[arg(long)]
+    fetch: bool,
+
+    /// With --fetch, only fetch and verify the package hash without extracting it
+    #[arg(long)]
+    verify_only: bool,
+
+    /// Destination prefix to extract fetched packages into
+    #[arg(long, default_value = "installed")]
+    dest_dir: PathBuf,
+
+    /// Content-addressable cache directory for skipping re-packaging of unchanged outputs
+    #[arg(long, default_value = "cache")]
+    cache_dir: PathBuf,
+
+    /// Fail if a freshly computed config_hash/content_hash diverges from buckos-binaries.lock
+    #[arg(long)]
+    locked: bool,
+
+    /// Path to the reproducibility lockfile written after packaging and read with --locked
+    #[arg(long, default_value = "buckos-binaries.lock")]
+    lockfile: PathBuf,
+
+    /// Hash algorithm(s) for SRI integrity strings (sha256, sha512); pass multiple times for more than one
+    #[arg(long, default_value = "sha256")]
+    hash_algo: Vec<String>,
+}
+
+// Usable for SRI (Subresource-Integrity-style) integrity strings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HashAlgo {
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgo {
+    fn parse(s: &str) -> Result<Self> {
+        match s {
+            "sha256" => Ok(HashAlgo::Sha256),
+            "sha512" => Ok(HashAlgo::Sha512),
+            other => anyhow::bail!("Unsupported hash algorithm: {} (expected sha256 or sha512)", other),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Sha512 => "sha512",
+        }
+    }
+
+    fn new_hasher(&self) -> Box<dyn digest::DynDigest> {
+        match self {
+            HashAlgo::Sha256 => Box::new(Sha256::new()),
+            HashAlgo::Sha512 => Box::new(Sha512::new()),
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -45,6 +118,50 @@ struct PackageMetadata {
     version: String,
     config_hash: String,
     content_hash: String,
+    integrity: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct LockEntry {
+    target: String,
+    name: String,
+    version: String,
+    config_hash: String,
+    content_hash: String,
+    tarball_sha256: String,
+}
+
+// Checked with --locked so a compiler upgrade or USE-flag change that silently alters outputs
+// fails CI loudly instead of shipping a divergent binary.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct Lockfile {
+    packages: Vec<LockEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct CacheEntry {
+    target: String,
+    config_hash: String,
+    content_hash: String,
+    // Sorted algo names (e.g. ["sha256", "sha512"]) `integrity` was computed for, so a hit
+    // requires the same --hash-algo set as this entry was stored with.
+    hash_algos: Vec<String>,
+    tarball_hash: String,
+    integrity: Vec<String>,
+    blob_path: PathBuf,
+}
+
+fn hash_algo_names(hash_algos: &[HashAlgo]) -> Vec<String> {
+    let mut names: Vec<String> = hash_algos.iter().map(|a| a.name().to_string()).collect();
+    names.sort();
+    names
+}
+
+// Index for the content-addressable cache rooted at --cache-dir; blobs live at
+// cache/<alg>/<hash[0:2]>/<hash>.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct CacheIndex {
+    entries: Vec<CacheEntry>,
 }
 
 fn main() -> Result<()> {
@@ -65,7 +182,10 @@ fn main() -> Result<()> {
     if args.skip_build {
         println!("Mode: Package already-built targets");
     }
-    if args.upload {
+    if args.fetch {
+        println!("Mode: Fetch prebuilt packages{}", if args.verify_only { " (verify only)" } else { "" });
+    }
+    if args.upload || args.fetch {
         if let Some(ref mirror) = args.mirror_url {
             println!("Mirror: {}", mirror);
         }
@@ -73,20 +193,64 @@ fn main() -> Result<()> {
     println!("{}", "=".repeat(60));
     println!();
 
+    let mirror_auth = MirrorAuth {
+        user: args.mirror_user.clone().or_else(|| std::env::var("BUCKOS_MIRROR_USER").ok()),
+        token: args.mirror_token.clone().or_else(|| std::env::var("BUCKOS_MIRROR_TOKEN").ok()),
+    };
+
     // Process targets in parallel
-    let results: Vec<_> = args
-        .targets
-        .par_iter()
-        .map(|target| {
-            package_target(
-                target,
-                &args.output_dir,
-                args.skip_build,
-                args.upload,
-                args.mirror_url.as_deref(),
-            )
-        })
-        .collect();
+    let results: Vec<_> = if args.fetch {
+        let mirror_url = args
+            .mirror_url
+            .as_deref()
+            .context("--fetch requires --mirror-url")?;
+
+        args.targets
+            .par_iter()
+            .map(|target| {
+                fetch_target(
+                    target,
+                    mirror_url,
+                    &mirror_auth,
+                    &args.dest_dir,
+                    args.verify_only,
+                    args.skip_build,
+                )
+            })
+            .collect()
+    } else {
+        let existing_lock = if args.locked {
+            Some(load_lockfile(&args.lockfile)?)
+        } else {
+            None
+        };
+
+        let hash_algos: Vec<HashAlgo> = args
+            .hash_algo
+            .iter()
+            .map(|s| HashAlgo::parse(s))
+            .collect::<Result<_>>()?;
+
+        let packaging_opts = PackagingOptions {
+            output_dir: &args.output_dir,
+            cache_dir: &args.cache_dir,
+            skip_build: args.skip_build,
+            upload: args.upload,
+            mirror_url: args.mirror_url.as_deref(),
+            mirror_auth: &mirror_auth,
+            hash_algos: &hash_algos,
+        };
+
+        args.targets
+            .par_iter()
+            .map(|target| {
+                let locked_entry = existing_lock
+                    .as_ref()
+                    .and_then(|lf| lf.packages.iter().find(|e| e.target == *target));
+                package_target(target, locked_entry, &packaging_opts)
+            })
+            .collect()
+    };
 
     // Count successes and failures
     let successful: Vec<_> = results.iter().filter_map(|r| r.as_ref().ok()).collect();
@@ -95,7 +259,8 @@ fn main() -> Result<()> {
     println!();
     println!("{}", "=".repeat(60));
     println!(
-        "Packaged {}/{} targets",
+        "{} {}/{} targets",
+        if args.fetch { "Fetched" } else { "Packaged" },
         successful.len(),
         args.targets.len()
     );
@@ -113,26 +278,96 @@ fn main() -> Result<()> {
         }
     }
 
+    // Record a lockfile of every successfully packaged target's hashes, for future --locked runs.
+    // Merge into any existing lockfile by target rather than overwriting it outright, so a run
+    // over a subset of targets (a CI matrix job, a retry after a partial failure) doesn't drop
+    // the recorded entries for targets it didn't touch this time.
+    if !args.fetch && !successful.is_empty() {
+        let mut packages = if args.lockfile.exists() {
+            load_lockfile(&args.lockfile)?.packages
+        } else {
+            Vec::new()
+        };
+
+        for pkg_path in &successful {
+            match lock_entry_from_sidecar(pkg_path) {
+                Ok(entry) => {
+                    packages.retain(|e| e.target != entry.target);
+                    packages.push(entry);
+                }
+                Err(e) => eprintln!(
+                    "Warning: could not record lockfile entry for {}: {}",
+                    pkg_path.display(),
+                    e
+                ),
+            }
+        }
+        packages.sort_by(|a, b| a.target.cmp(&b.target));
+
+        save_lockfile(&args.lockfile, &Lockfile { packages })?;
+        println!();
+        println!("✓ Wrote lockfile: {}", args.lockfile.display());
+    }
+
     Ok(())
 }
 
-fn package_target(
-    target: &str,
-    output_dir: &Path,
+// Settings that are constant across a whole `package_target` run over `args.targets`, bundled
+// so the function signature doesn't grow with every mirror/cache knob.
+struct PackagingOptions<'a> {
+    output_dir: &'a Path,
+    cache_dir: &'a Path,
     skip_build: bool,
     upload: bool,
-    mirror_url: Option<&str>,
+    mirror_url: Option<&'a str>,
+    mirror_auth: &'a MirrorAuth,
+    hash_algos: &'a [HashAlgo],
+}
+
+// Credential for an HTTP(S) mirror: a bearer token, or a basic-auth username paired with the
+// token as the password when a username is present.
+struct MirrorAuth {
+    user: Option<String>,
+    token: Option<String>,
+}
+
+// Identifying fields for a single package, threaded through cache and sidecar helpers together
+// instead of as five separate &str parameters.
+struct PackageContext<'a> {
+    target: &'a str,
+    package_name: &'a str,
+    version: &'a str,
+    config_hash: &'a str,
+    content_hash: &'a str,
+}
+
+fn package_target(
+    target: &str,
+    locked_entry: Option<&LockEntry>,
+    opts: &PackagingOptions,
 ) -> Result<PathBuf> {
     println!("Packaging: {}", target);
 
     // Get target info
-    let info = get_target_info(target, skip_build)?;
+    let info = get_target_info(target, opts.skip_build)?;
 
     // Calculate config hash
-    let config_hash = calculate_config_hash(target, skip_build)?;
+    let config_hash = calculate_config_hash(target, opts.skip_build)?;
+
+    if let Some(locked) = locked_entry {
+        if locked.config_hash != config_hash {
+            anyhow::bail!(
+                "--locked: config_hash for {} diverges from buckos-binaries.lock (recorded {}, computed {}); \
+                 a compiler upgrade or USE-flag change likely altered the build configuration",
+                target,
+                locked.config_hash,
+                config_hash
+            );
+        }
+    }
 
     // Build or find target
-    let output_path = if skip_build {
+    let output_path = if opts.skip_build {
         find_built_package(target)?
     } else {
         build_target(target)?
@@ -141,25 +376,86 @@ fn package_target(
     // Calculate file hash
     let file_hash = calculate_file_hash(&output_path)?;
 
-    // Create package
-    let package_path = create_package(
+    if let Some(locked) = locked_entry {
+        if locked.content_hash != file_hash {
+            anyhow::bail!(
+                "--locked: content_hash for {} diverges from buckos-binaries.lock (recorded {}, computed {}); \
+                 the build output changed without a matching lockfile update",
+                target,
+                locked.content_hash,
+                file_hash
+            );
+        }
+    }
+
+    let ctx = PackageContext {
         target,
-        &output_path,
-        &info.name,
-        &info.version,
-        &config_hash,
-        &file_hash,
-        output_dir,
-    )?;
+        package_name: &info.name,
+        version: &info.version,
+        config_hash: &config_hash,
+        content_hash: &file_hash,
+    };
+
+    // Create package, reusing a cached tarball if one already exists for this
+    // (target, config_hash, content_hash) triple.
+    fs::create_dir_all(opts.output_dir)?;
+    // A previous output may be a hard link to a cache blob; unlink it rather than
+    // overwrite it in place, which would rewrite (or empty) the blob as well.
+    let _ = fs::remove_file(
+        opts.output_dir
+            .join(package_filename(ctx.package_name, ctx.version, ctx.config_hash)),
+    );
+    let package_path = if let Some(cached) = cache_lookup(
+        opts.cache_dir,
+        ctx.target,
+        ctx.config_hash,
+        ctx.content_hash,
+        opts.hash_algos,
+    )? {
+        println!(
+            "✓ Cache hit for {} (content_hash {}), reusing {}",
+            target,
+            file_hash,
+            cached.blob_path.display()
+        );
+
+        let package_path = opts
+            .output_dir
+            .join(package_filename(ctx.package_name, ctx.version, ctx.config_hash));
+        if fs::hard_link(&cached.blob_path, &package_path).is_err() {
+            fs::copy(&cached.blob_path, &package_path)?;
+        }
+        write_sidecar(
+            opts.output_dir,
+            &package_filename(ctx.package_name, ctx.version, ctx.config_hash),
+            &cached.tarball_hash,
+            &cached.integrity,
+            &ctx,
+        )?;
+        package_path
+    } else {
+        let (package_path, integrity) =
+            create_package(&output_path, opts.output_dir, opts.hash_algos, &ctx)?;
+        let tarball_hash = calculate_tarball_sha256(&package_path)?;
+        cache_store(
+            opts.cache_dir,
+            opts.hash_algos,
+            &tarball_hash,
+            &integrity,
+            &package_path,
+            &ctx,
+        )?;
+        package_path
+    };
 
     // Upload if requested
-    if upload {
-        if let Some(mirror) = mirror_url {
-            upload_package(&package_path, mirror)?;
+    if opts.upload {
+        if let Some(mirror) = opts.mirror_url {
+            upload_package(&package_path, mirror, opts.mirror_auth)?;
             // Also upload .sha256 file
             let hash_path = package_path.with_extension("tar.gz.sha256");
             if hash_path.exists() {
-                upload_package(&hash_path, mirror)?;
+                upload_package(&hash_path, mirror, opts.mirror_auth)?;
             }
         }
     }
@@ -400,16 +696,94 @@ fn calculate_file_hash(path: &Path) -> Result<String> {
     Ok(hash[..16].to_string())
 }
 
+// Unlike Builder::append_dir_all, preserves symlinks (rather than dereferencing them) and Unix
+// permission bits, and zeroes mtime/uid/gid so the tarball's bytes are stable across machines.
+fn append_tree_reproducible<W: Write>(
+    tar: &mut tar::Builder<W>,
+    package_name: &str,
+    output_path: &Path,
+) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut entries: Vec<_> = WalkDir::new(output_path)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .collect();
+    entries.sort_by_key(|e| e.path().to_path_buf());
+
+    for entry in entries {
+        let rel_path = entry.path().strip_prefix(output_path).unwrap();
+        if rel_path.as_os_str().is_empty() {
+            continue;
+        }
+        let archive_path = Path::new(package_name).join(rel_path);
+
+        let metadata = entry.path().symlink_metadata()?;
+        let file_type = metadata.file_type();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(0);
+        header.set_uid(0);
+        header.set_gid(0);
+
+        if file_type.is_symlink() {
+            let link_target = fs::read_link(entry.path())?;
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            header.set_mode(0o777);
+            header.set_cksum();
+            tar.append_link(&mut header, &archive_path, &link_target)?;
+        } else if file_type.is_dir() {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_size(0);
+            header.set_mode(metadata.permissions().mode() & 0o7777);
+            header.set_cksum();
+            tar.append_data(&mut header, &archive_path, std::io::empty())?;
+        } else {
+            append_xattrs(tar, entry.path())?;
+
+            let mut file = File::open(entry.path())?;
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(metadata.len());
+            header.set_mode(metadata.permissions().mode() & 0o7777);
+            header.set_cksum();
+            tar.append_data(&mut header, &archive_path, &mut file)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Written as SCHILY.xattr.<name> PAX headers, the convention GNU tar uses.
+fn append_xattrs<W: Write>(tar: &mut tar::Builder<W>, path: &Path) -> Result<()> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(_) => return Ok(()),
+    };
+
+    let mut extensions = Vec::new();
+    for name in names {
+        if let Some(value) = xattr::get(path, &name)? {
+            extensions.push((format!("SCHILY.xattr.{}", name.to_string_lossy()), value));
+        }
+    }
+
+    if extensions.is_empty() {
+        return Ok(());
+    }
+
+    tar.append_pax_extensions(extensions.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+
+    Ok(())
+}
+
 fn create_package(
-    target: &str,
     output_path: &Path,
-    package_name: &str,
-    version: &str,
-    config_hash: &str,
-    file_hash: &str,
     output_dir: &Path,
-) -> Result<PathBuf> {
-    let package_filename = format!("{}-{}-{}-bin.tar.gz", package_name, version, config_hash);
+    hash_algos: &[HashAlgo],
+    ctx: &PackageContext,
+) -> Result<(PathBuf, Vec<String>)> {
+    let package_filename = package_filename(ctx.package_name, ctx.version, ctx.config_hash);
     let package_path = output_dir.join(&package_filename);
 
     println!("Creating package: {}", package_filename);
@@ -422,19 +796,23 @@ fn create_package(
     let mut tar = tar::Builder::new(enc);
 
     if output_path.is_dir() {
-        tar.append_dir_all(package_name, output_path)?;
+        append_tree_reproducible(&mut tar, ctx.package_name, output_path)?;
     } else {
         let file_name = output_path.file_name().unwrap();
         tar.append_path_with_name(output_path, file_name)?;
     }
 
-    // Add metadata
+    // Add metadata. `integrity` covers the pre-tar build output (same basis as
+    // `content_hash`) since the tarball itself isn't finalized yet.
+    let content_integrity = compute_integrity(output_path, hash_algos)?;
+
     let metadata = PackageMetadata {
-        target: target.to_string(),
-        name: package_name.to_string(),
-        version: version.to_string(),
-        config_hash: config_hash.to_string(),
-        content_hash: file_hash.to_string(),
+        target: ctx.target.to_string(),
+        name: ctx.package_name.to_string(),
+        version: ctx.version.to_string(),
+        config_hash: ctx.config_hash.to_string(),
+        content_hash: ctx.content_hash.to_string(),
+        integrity: content_integrity,
     };
 
     let metadata_json = serde_json::to_string_pretty(&metadata)?;
@@ -444,48 +822,48 @@ fn create_package(
     header.set_cksum();
     tar.append_data(&mut header, "METADATA.json", metadata_json.as_bytes())?;
 
-    tar.finish()?;
+    // `Builder::finish` only flushes the tar stream; the gzip encoder it wraps
+    // still needs an explicit `finish` to write its footer before we reopen
+    // the file below.
+    tar.into_inner()?.finish()?;
 
     // Calculate SHA256 of tarball
-    let mut file = File::open(&package_path)?;
-    let mut hasher = Sha256::new();
-    let mut buffer = [0u8; 8192];
-    loop {
-        let n = file.read(&mut buffer)?;
-        if n == 0 {
-            break;
-        }
-        hasher.update(&buffer[..n]);
-    }
-    let tarball_hash = format!("{:x}", hasher.finalize());
+    let tarball_hash = calculate_tarball_sha256(&package_path)?;
 
-    // Create .sha256 file
-    let hash_filename = format!("{}.sha256", package_filename);
-    let hash_path = output_dir.join(&hash_filename);
+    // SRI integrity of the final tarball, which is what fetch/install verifies against.
+    let tarball_integrity = compute_integrity(&package_path, hash_algos)?;
 
-    let mut hash_file = File::create(&hash_path)?;
-    writeln!(hash_file, "{}  {}", tarball_hash, package_filename)?;
-    writeln!(hash_file, "# Config Hash: {}", config_hash)?;
-    writeln!(hash_file, "# Content Hash: {}", file_hash)?;
-    writeln!(hash_file, "# Package: {}", package_name)?;
-    writeln!(hash_file, "# Version: {}", version)?;
-    writeln!(hash_file, "# Target: {}", target)?;
+    // Create .sha256 file
+    let hash_path = write_sidecar(output_dir, &package_filename, &tarball_hash, &tarball_integrity, ctx)?;
 
     let size = fs::metadata(&package_path)?.len();
     println!("✓ Created: {}", package_path.display());
     println!("  Size: {:.2} MB", size as f64 / 1024.0 / 1024.0);
     println!("✓ Created: {}", hash_path.display());
     println!("  Tarball SHA256: {}...", &tarball_hash[..16]);
-    println!("  Config Hash: {}", config_hash);
-    println!("  Content Hash: {}", file_hash);
+    println!("  Config Hash: {}", ctx.config_hash);
+    println!("  Content Hash: {}", ctx.content_hash);
+    if !tarball_integrity.is_empty() {
+        println!("  Integrity: {}", tarball_integrity.join(", "));
+    }
 
-    Ok(package_path)
+    Ok((package_path, tarball_integrity))
 }
 
-fn upload_package(package_path: &Path, mirror_url: &str) -> Result<()> {
+fn upload_package(package_path: &Path, mirror_url: &str, mirror_auth: &MirrorAuth) -> Result<()> {
     println!("Uploading {} to {}...", package_path.display(), mirror_url);
 
-    if mirror_url.starts_with('/') {
+    if mirror_url.starts_with("http://") || mirror_url.starts_with("https://") {
+        let url = format!(
+            "{}/{}",
+            mirror_url.trim_end_matches('/'),
+            package_path.file_name().unwrap().to_string_lossy()
+        );
+        let body = fs::read(package_path)
+            .with_context(|| format!("Failed to read {}", package_path.display()))?;
+        http_put_with_retry(&url, body, mirror_auth)?;
+        println!("✓ Uploaded: {}", url);
+    } else if mirror_url.starts_with('/') {
         // Local path
         let dest = PathBuf::from(mirror_url).join(package_path.file_name().unwrap());
         fs::create_dir_all(dest.parent().unwrap())?;
@@ -506,3 +884,535 @@ fn upload_package(package_path: &Path, mirror_url: &str) -> Result<()> {
 
     Ok(())
 }
+
+fn download_file(mirror_url: &str, filename: &str, dest: &Path, mirror_auth: &MirrorAuth) -> Result<()> {
+    if mirror_url.starts_with("http://") || mirror_url.starts_with("https://") {
+        let url = format!("{}/{}", mirror_url.trim_end_matches('/'), filename);
+        let body = http_get_with_retry(&url, mirror_auth)?;
+        fs::write(dest, body).with_context(|| format!("Failed to write {}", dest.display()))?;
+    } else if let Some(local_dir) = mirror_url.strip_prefix('/') {
+        let src = PathBuf::from("/").join(local_dir).join(filename);
+        fs::copy(&src, dest)
+            .with_context(|| format!("Failed to copy {} from local mirror", src.display()))?;
+    } else {
+        let remote = format!("{}/{}", mirror_url.trim_end_matches('/'), filename);
+        let status = Command::new("scp")
+            .arg(&remote)
+            .arg(dest)
+            .status()
+            .with_context(|| format!("Failed to run scp for {}", remote))?;
+
+        if !status.success() {
+            anyhow::bail!("Failed to fetch {} via scp", remote);
+        }
+    }
+
+    Ok(())
+}
+
+// Give up on a transient 5xx from the mirror after this many attempts.
+const HTTP_MAX_ATTEMPTS: u32 = 4;
+
+fn http_client() -> Result<reqwest::blocking::Client> {
+    reqwest::blocking::Client::builder()
+        .build()
+        .context("Failed to build HTTP client")
+}
+
+fn apply_auth(
+    builder: reqwest::blocking::RequestBuilder,
+    mirror_auth: &MirrorAuth,
+) -> reqwest::blocking::RequestBuilder {
+    match (&mirror_auth.user, &mirror_auth.token) {
+        (Some(user), token) => builder.basic_auth(user, token.as_deref()),
+        (None, Some(token)) => builder.bearer_auth(token),
+        (None, None) => builder,
+    }
+}
+
+fn http_put_with_retry(url: &str, body: Vec<u8>, mirror_auth: &MirrorAuth) -> Result<()> {
+    let client = http_client()?;
+    let mut backoff = Duration::from_secs(1);
+
+    for attempt in 1..=HTTP_MAX_ATTEMPTS {
+        let request = apply_auth(client.put(url), mirror_auth).body(body.clone());
+        let response = request
+            .send()
+            .with_context(|| format!("Failed to PUT {}", url))?;
+        let status = response.status();
+
+        if status.is_success() {
+            return Ok(());
+        }
+
+        if status.is_server_error() && attempt < HTTP_MAX_ATTEMPTS {
+            eprintln!(
+                "PUT {} returned {} (attempt {}/{}), retrying in {:?}...",
+                url, status, attempt, HTTP_MAX_ATTEMPTS, backoff
+            );
+            std::thread::sleep(backoff);
+            backoff *= 2;
+            continue;
+        }
+
+        anyhow::bail!("PUT {} failed with status {}", url, status);
+    }
+
+    unreachable!("loop always returns or bails")
+}
+
+fn http_get_with_retry(url: &str, mirror_auth: &MirrorAuth) -> Result<Vec<u8>> {
+    let client = http_client()?;
+    let mut backoff = Duration::from_secs(1);
+
+    for attempt in 1..=HTTP_MAX_ATTEMPTS {
+        let request = apply_auth(client.get(url), mirror_auth);
+        let response = request
+            .send()
+            .with_context(|| format!("Failed to GET {}", url))?;
+        let status = response.status();
+
+        if status.is_success() {
+            return response
+                .bytes()
+                .map(|b| b.to_vec())
+                .with_context(|| format!("Failed to read response body for {}", url));
+        }
+
+        if status.is_server_error() && attempt < HTTP_MAX_ATTEMPTS {
+            eprintln!(
+                "GET {} returned {} (attempt {}/{}), retrying in {:?}...",
+                url, status, attempt, HTTP_MAX_ATTEMPTS, backoff
+            );
+            std::thread::sleep(backoff);
+            backoff *= 2;
+            continue;
+        }
+
+        anyhow::bail!("GET {} failed with status {}", url, status);
+    }
+
+    unreachable!("loop always returns or bails")
+}
+
+fn calculate_tarball_sha256(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 8192];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn calculate_digest(path: &Path, algo: HashAlgo) -> Result<Vec<u8>> {
+    let mut hasher = algo.new_hasher();
+
+    if path.is_dir() {
+        let mut entries: Vec<_> = WalkDir::new(path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .collect();
+        entries.sort_by_key(|e| e.path().to_path_buf());
+
+        for entry in entries {
+            let mut file = File::open(entry.path())?;
+            let mut buffer = [0u8; 8192];
+            loop {
+                let n = file.read(&mut buffer)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..n]);
+            }
+        }
+    } else {
+        let mut file = File::open(path)?;
+        let mut buffer = [0u8; 8192];
+        loop {
+            let n = file.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+        }
+    }
+
+    Ok(hasher.finalize_reset().to_vec())
+}
+
+// <alg>-<base64(rawdigest)>
+fn to_sri(algo: HashAlgo, raw: &[u8]) -> String {
+    format!("{}-{}", algo.name(), base64::engine::general_purpose::STANDARD.encode(raw))
+}
+
+fn parse_sri(sri: &str) -> Result<(HashAlgo, Vec<u8>)> {
+    let (alg, b64) = sri
+        .split_once('-')
+        .with_context(|| format!("Malformed integrity string: {}", sri))?;
+    let algo = HashAlgo::parse(alg)?;
+    let raw = base64::engine::general_purpose::STANDARD
+        .decode(b64)
+        .with_context(|| format!("Malformed integrity string: {}", sri))?;
+    Ok((algo, raw))
+}
+
+fn compute_integrity(path: &Path, hash_algos: &[HashAlgo]) -> Result<Vec<String>> {
+    hash_algos
+        .iter()
+        .map(|algo| Ok(to_sri(*algo, &calculate_digest(path, *algo)?)))
+        .collect()
+}
+
+fn parse_sha256_sidecar(contents: &str) -> Result<(String, Option<String>, Vec<String>)> {
+    let mut expected_tarball_hash = None;
+    let mut config_hash = None;
+    let mut integrity = Vec::new();
+
+    for line in contents.lines() {
+        if let Some(rest) = line.strip_prefix("# Config Hash: ") {
+            config_hash = Some(rest.trim().to_string());
+        } else if let Some(rest) = line.strip_prefix("# Integrity: ") {
+            integrity.push(rest.trim().to_string());
+        } else if expected_tarball_hash.is_none() {
+            if let Some(hash) = line.split_whitespace().next() {
+                expected_tarball_hash = Some(hash.to_string());
+            }
+        }
+    }
+
+    let expected_tarball_hash =
+        expected_tarball_hash.context("Malformed .sha256 file: missing tarball hash line")?;
+
+    Ok((expected_tarball_hash, config_hash, integrity))
+}
+
+// Fetch a prebuilt package, verify it against the companion .sha256, and (unless
+// verify_only) extract it into dest_dir.
+fn fetch_target(
+    target: &str,
+    mirror_url: &str,
+    mirror_auth: &MirrorAuth,
+    dest_dir: &Path,
+    verify_only: bool,
+    skip_build: bool,
+) -> Result<PathBuf> {
+    println!("Fetching: {}", target);
+
+    let info = get_target_info(target, skip_build)?;
+    let config_hash = calculate_config_hash(target, skip_build)?;
+
+    let package_filename = package_filename(&info.name, &info.version, &config_hash);
+    let hash_filename = format!("{}.sha256", package_filename);
+
+    let tmp_dir =
+        std::env::temp_dir().join(format!("package-binary-fetch-{}", std::process::id()));
+    fs::create_dir_all(&tmp_dir)?;
+    let package_path = tmp_dir.join(&package_filename);
+    let hash_path = tmp_dir.join(&hash_filename);
+
+    download_file(mirror_url, &package_filename, &package_path, mirror_auth)?;
+    download_file(mirror_url, &hash_filename, &hash_path, mirror_auth)?;
+
+    let hash_contents = fs::read_to_string(&hash_path)
+        .with_context(|| format!("Failed to read {}", hash_path.display()))?;
+    let (expected_tarball_hash, remote_config_hash, integrity) =
+        parse_sha256_sidecar(&hash_contents)?;
+
+    if let Some(remote_config_hash) = &remote_config_hash {
+        if remote_config_hash != &config_hash {
+            anyhow::bail!(
+                "Config hash mismatch for {}: mirror package was built under config_hash {} \
+                 but the local config_hash is {} (the remote was likely built with a different \
+                 toolchain/USE-flag configuration)",
+                target,
+                remote_config_hash,
+                config_hash
+            );
+        }
+    }
+
+    let actual_tarball_hash = calculate_tarball_sha256(&package_path)?;
+    if actual_tarball_hash != expected_tarball_hash {
+        anyhow::bail!(
+            "SHA256 mismatch for {}: expected {}, got {}",
+            package_filename,
+            expected_tarball_hash,
+            actual_tarball_hash
+        );
+    }
+
+    for sri in &integrity {
+        let (algo, expected_raw) = parse_sri(sri)?;
+        let actual_raw = calculate_digest(&package_path, algo)?;
+        if actual_raw != expected_raw {
+            anyhow::bail!(
+                "Integrity mismatch for {} ({}): expected {}, got {}",
+                package_filename,
+                algo.name(),
+                sri,
+                to_sri(algo, &actual_raw)
+            );
+        }
+    }
+
+    println!("✓ Verified: {} ({})", package_filename, actual_tarball_hash);
+    if !integrity.is_empty() {
+        println!("  Integrity: {}", integrity.join(", "));
+    }
+
+    if verify_only {
+        return Ok(package_path);
+    }
+
+    fs::create_dir_all(dest_dir)?;
+    let tar_gz = File::open(&package_path)?;
+    let dec = flate2::read::GzDecoder::new(tar_gz);
+    let mut archive = tar::Archive::new(dec);
+    archive
+        .unpack(dest_dir)
+        .with_context(|| format!("Failed to extract {} into {}", package_filename, dest_dir.display()))?;
+
+    println!("✓ Installed: {} -> {}", package_filename, dest_dir.display());
+
+    Ok(dest_dir.join(&info.name))
+}
+
+fn package_filename(package_name: &str, version: &str, config_hash: &str) -> String {
+    format!("{}-{}-{}-bin.tar.gz", package_name, version, config_hash)
+}
+
+fn write_sidecar(
+    output_dir: &Path,
+    package_filename: &str,
+    tarball_hash: &str,
+    integrity: &[String],
+    ctx: &PackageContext,
+) -> Result<PathBuf> {
+    let hash_path = output_dir.join(format!("{}.sha256", package_filename));
+
+    let mut hash_file = File::create(&hash_path)?;
+    writeln!(hash_file, "{}  {}", tarball_hash, package_filename)?;
+    writeln!(hash_file, "# Config Hash: {}", ctx.config_hash)?;
+    writeln!(hash_file, "# Content Hash: {}", ctx.content_hash)?;
+    writeln!(hash_file, "# Package: {}", ctx.package_name)?;
+    writeln!(hash_file, "# Version: {}", ctx.version)?;
+    writeln!(hash_file, "# Target: {}", ctx.target)?;
+    for sri in integrity {
+        writeln!(hash_file, "# Integrity: {}", sri)?;
+    }
+
+    Ok(hash_path)
+}
+
+fn cache_index_path(cache_dir: &Path) -> PathBuf {
+    cache_dir.join("index.json")
+}
+
+fn load_cache_index(cache_dir: &Path) -> Result<CacheIndex> {
+    let path = cache_index_path(cache_dir);
+    if !path.exists() {
+        return Ok(CacheIndex::default());
+    }
+
+    let contents =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&contents).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+fn save_cache_index(cache_dir: &Path, index: &CacheIndex) -> Result<()> {
+    fs::create_dir_all(cache_dir)?;
+    let contents = serde_json::to_string_pretty(index)?;
+    fs::write(cache_index_path(cache_dir), contents)?;
+    Ok(())
+}
+
+// cache/<alg>/<hash[0:2]>/<hash>
+fn cache_blob_path(cache_dir: &Path, alg: &str, hash: &str) -> PathBuf {
+    cache_dir.join(alg).join(&hash[..2]).join(hash)
+}
+
+fn cache_lookup(
+    cache_dir: &Path,
+    target: &str,
+    config_hash: &str,
+    content_hash: &str,
+    hash_algos: &[HashAlgo],
+) -> Result<Option<CacheEntry>> {
+    // Only reuse an entry computed for exactly the requested algo set, so a hit never serves
+    // integrity that's missing an algo the caller asked for this time around.
+    let requested = hash_algo_names(hash_algos);
+    let index = load_cache_index(cache_dir)?;
+    Ok(index.entries.into_iter().find(|e| {
+        e.target == target
+            && e.config_hash == config_hash
+            && e.content_hash == content_hash
+            && e.hash_algos == requested
+    }))
+}
+
+// Guards the blob-existence check, copy, and index read-modify-write below against concurrent
+// rayon workers (e.g. two targets racing to store the same tarball_hash).
+fn cache_store_lock() -> &'static Mutex<()> {
+    static LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+    LOCK.get_or_init(|| Mutex::new(()))
+}
+
+fn cache_store(
+    cache_dir: &Path,
+    hash_algos: &[HashAlgo],
+    tarball_hash: &str,
+    integrity: &[String],
+    package_path: &Path,
+    ctx: &PackageContext,
+) -> Result<PathBuf> {
+    let blob_path = cache_blob_path(cache_dir, "sha256", tarball_hash);
+    fs::create_dir_all(blob_path.parent().unwrap())?;
+
+    let _guard = cache_store_lock().lock().unwrap();
+
+    if !blob_path.exists() {
+        // Write to a per-process temp path and rename into place so a reader never sees a
+        // partially-copied blob, even though the mutex already serializes writers.
+        let tmp_path = blob_path.with_file_name(format!(
+            "{}.tmp-{}",
+            blob_path.file_name().unwrap().to_string_lossy(),
+            std::process::id()
+        ));
+        fs::copy(package_path, &tmp_path)?;
+        fs::rename(&tmp_path, &blob_path)?;
+    }
+
+    let mut index = load_cache_index(cache_dir)?;
+    index.entries.retain(|e| {
+        !(e.target == ctx.target && e.config_hash == ctx.config_hash && e.content_hash == ctx.content_hash)
+    });
+    index.entries.push(CacheEntry {
+        target: ctx.target.to_string(),
+        config_hash: ctx.config_hash.to_string(),
+        content_hash: ctx.content_hash.to_string(),
+        hash_algos: hash_algo_names(hash_algos),
+        tarball_hash: tarball_hash.to_string(),
+        integrity: integrity.to_vec(),
+        blob_path: blob_path.clone(),
+    });
+    save_cache_index(cache_dir, &index)?;
+
+    Ok(blob_path)
+}
+
+fn load_lockfile(path: &Path) -> Result<Lockfile> {
+    let contents = fs::read_to_string(path).with_context(|| {
+        format!(
+            "Failed to read lockfile {} (run without --locked first to generate one)",
+            path.display()
+        )
+    })?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse lockfile {}", path.display()))
+}
+
+fn save_lockfile(path: &Path, lockfile: &Lockfile) -> Result<()> {
+    let contents = serde_json::to_string_pretty(lockfile)?;
+    fs::write(path, contents).with_context(|| format!("Failed to write lockfile {}", path.display()))
+}
+
+fn lock_entry_from_sidecar(package_path: &Path) -> Result<LockEntry> {
+    let mut hash_path = package_path.as_os_str().to_owned();
+    hash_path.push(".sha256");
+    let hash_path = PathBuf::from(hash_path);
+    let contents = fs::read_to_string(&hash_path)
+        .with_context(|| format!("Failed to read {}", hash_path.display()))?;
+
+    let mut tarball_sha256 = None;
+    let mut config_hash = None;
+    let mut content_hash = None;
+    let mut name = None;
+    let mut version = None;
+    let mut target = None;
+
+    for line in contents.lines() {
+        if let Some(rest) = line.strip_prefix("# Config Hash: ") {
+            config_hash = Some(rest.trim().to_string());
+        } else if let Some(rest) = line.strip_prefix("# Content Hash: ") {
+            content_hash = Some(rest.trim().to_string());
+        } else if let Some(rest) = line.strip_prefix("# Package: ") {
+            name = Some(rest.trim().to_string());
+        } else if let Some(rest) = line.strip_prefix("# Version: ") {
+            version = Some(rest.trim().to_string());
+        } else if let Some(rest) = line.strip_prefix("# Target: ") {
+            target = Some(rest.trim().to_string());
+        } else if tarball_sha256.is_none() {
+            if let Some(hash) = line.split_whitespace().next() {
+                tarball_sha256 = Some(hash.to_string());
+            }
+        }
+    }
+
+    Ok(LockEntry {
+        target: target.context("Malformed .sha256 file: missing Target")?,
+        name: name.context("Malformed .sha256 file: missing Package")?,
+        version: version.context("Malformed .sha256 file: missing Version")?,
+        config_hash: config_hash.context("Malformed .sha256 file: missing Config Hash")?,
+        content_hash: content_hash.context("Malformed .sha256 file: missing Content Hash")?,
+        tarball_sha256: tarball_sha256.context("Malformed .sha256 file: missing tarball hash line")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_filename_format() {
+        assert_eq!(
+            package_filename("foo", "1.2.3", "abcdef1234567890"),
+            "foo-1.2.3-abcdef1234567890-bin.tar.gz"
+        );
+    }
+
+    #[test]
+    fn parse_sha256_sidecar_round_trip() {
+        let contents = "deadbeef  foo-1.0-abc-bin.tar.gz\n\
+                         # Config Hash: abc\n\
+                         # Content Hash: def\n\
+                         # Package: foo\n\
+                         # Version: 1.0\n\
+                         # Target: //foo:foo\n\
+                         # Integrity: sha256-aGVsbG8=\n\
+                         # Integrity: sha512-d29ybGQ=\n";
+
+        let (tarball_hash, config_hash, integrity) = parse_sha256_sidecar(contents).unwrap();
+        assert_eq!(tarball_hash, "deadbeef");
+        assert_eq!(config_hash.as_deref(), Some("abc"));
+        assert_eq!(integrity, vec!["sha256-aGVsbG8=", "sha512-d29ybGQ="]);
+    }
+
+    #[test]
+    fn parse_sha256_sidecar_missing_hash_line_errors() {
+        let contents = "# Config Hash: abc\n";
+        assert!(parse_sha256_sidecar(contents).is_err());
+    }
+
+    #[test]
+    fn sri_round_trip() {
+        for algo in [HashAlgo::Sha256, HashAlgo::Sha512] {
+            let raw = b"some digest bytes".to_vec();
+            let sri = to_sri(algo, &raw);
+            let (parsed_algo, parsed_raw) = parse_sri(&sri).unwrap();
+            assert_eq!(parsed_algo, algo);
+            assert_eq!(parsed_raw, raw);
+        }
+    }
+
+    #[test]
+    fn parse_sri_rejects_malformed_input() {
+        assert!(parse_sri("unsupportedalgo-aGVsbG8=").is_err());
+        assert!(parse_sri("sha256-not valid base64!!").is_err());
+    }
+}